@@ -0,0 +1,522 @@
+//! Pure bit-array/codec logic shared by the `bitarray` NIF crate, kept free
+//! of any `rustler` dependency so it can be unit-tested with a plain
+//! `cargo test` instead of linking against the BEAM's `enif_*` symbols.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+pub const CHUNK_SIZE_U64: usize = 1024;
+
+/// Error conditions from malformed/adversarial input or out-of-range
+/// caller-supplied positions. The `bitarray` crate maps each variant to its
+/// own NIF error atom.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CoreError {
+    InvalidMagic,
+    UnsupportedVersion,
+    TruncatedHeader,
+    BitLengthMismatch,
+    TruncatedRecord,
+    InvalidChunkTag,
+    ChunkOverrunsArray,
+    TruncatedChunk,
+    InvalidRunTag,
+    IndexOutOfBounds,
+}
+
+pub fn num_chunks(num_words: usize) -> usize {
+    num_words.div_ceil(CHUNK_SIZE_U64)
+}
+
+// Release, not Relaxed: pairs with the Acquire half of the flush's clearing
+// RMW, so a flush that observes this dirty bit set also sees the data write
+// it covers, instead of possibly clearing a dirty bit for a write it can't
+// see yet.
+pub fn mark_chunk_dirty(dirty: &[AtomicU64], chunk_num: usize) {
+    let mask = 1u64 << (chunk_num % 64);
+    dirty[chunk_num / 64].fetch_or(mask, Ordering::Release);
+}
+
+pub fn mark_word_dirty(dirty: &[AtomicU64], word_index: usize) {
+    mark_chunk_dirty(dirty, word_index / CHUNK_SIZE_U64);
+}
+
+// Header for the self-describing container format: magic, a u16 format
+// version, u16 flags (currently unused, reserved for future codecs), the
+// total bit length as u64, and a reserved u64 for forward compatibility.
+pub const HEADER_MAGIC: &[u8; 4] = b"FBLM";
+pub const HEADER_VERSION: u16 = 1;
+pub const HEADER_SIZE: usize = 4 + 2 + 2 + 8 + 8;
+
+#[derive(Debug)]
+pub struct Header {
+    pub version: u16,
+    pub flags: u16,
+    pub bit_length: u64,
+}
+
+pub fn parse_header_bytes(bin: &[u8]) -> Result<Header, CoreError> {
+    if bin.len() < HEADER_SIZE {
+        return Err(CoreError::TruncatedHeader);
+    }
+    if &bin[0..4] != HEADER_MAGIC {
+        return Err(CoreError::InvalidMagic);
+    }
+    let version = u16::from_le_bytes([bin[4], bin[5]]);
+    if version != HEADER_VERSION {
+        return Err(CoreError::UnsupportedVersion);
+    }
+    let flags = u16::from_le_bytes([bin[6], bin[7]]);
+    let bit_length = u64::from_le_bytes(bin[8..16].try_into().unwrap());
+
+    Ok(Header { version, flags, bit_length })
+}
+
+pub fn build_header_bytes(bit_length: u64) -> [u8; HEADER_SIZE] {
+    let mut bin = [0u8; HEADER_SIZE];
+    bin[0..4].copy_from_slice(HEADER_MAGIC);
+    bin[4..6].copy_from_slice(&HEADER_VERSION.to_le_bytes());
+    bin[6..8].copy_from_slice(&0u16.to_le_bytes());
+    bin[8..16].copy_from_slice(&bit_length.to_le_bytes());
+    bin[16..24].copy_from_slice(&0u64.to_le_bytes());
+    bin
+}
+
+// Validates a container header's magic/version against a target resource's
+// bit length, for a caller to run once before streaming the chunk records
+// that follow it, instead of re-checking the header on every record.
+pub fn validate_header_bytes(header_bytes: &[u8], target_bit_length: u64) -> Result<Header, CoreError> {
+    let header = parse_header_bytes(header_bytes)?;
+    if header.bit_length != target_bit_length {
+        return Err(CoreError::BitLengthMismatch);
+    }
+    Ok(header)
+}
+
+// Shared by `or_chunk` and `or_chunk_record`: ORs `bytes` into `data` at
+// `byte_offset`, bounds-checking the write range against the array first
+// instead of indexing straight into it, since the input may be a malformed
+// or adversarial transfer.
+pub fn merge_bytes_into(data: &[AtomicU64], dirty: &[AtomicU64], bytes: &[u8], byte_offset: usize) -> Result<usize, CoreError> {
+    let total_bytes = data.len() * 8;
+    let end = byte_offset
+        .checked_add(bytes.len())
+        .filter(|&end| end <= total_bytes)
+        .ok_or(CoreError::ChunkOverrunsArray)?;
+
+    for (x, &byte) in bytes.iter().enumerate() {
+        let data_index = (x + byte_offset) / 8;
+        let bin_offset = (x + byte_offset) % 8;
+
+        data[data_index].fetch_or((byte as u64) << (bin_offset * 8), Ordering::Relaxed);
+        mark_word_dirty(dirty, data_index);
+    }
+
+    Ok(end)
+}
+
+// Chunk-record framing used by `to_bin_chunked_record`/`or_chunk_record`: a
+// tag byte followed by a u32 little-endian payload length and the payload
+// itself, so each chunk is self-delimiting and doesn't need the container
+// header re-sent alongside it.
+pub const CHUNK_RECORD_TAG_DATA: u8 = 0x01;
+pub const CHUNK_RECORD_HEADER_SIZE: usize = 1 + 4;
+
+pub fn encode_chunk_record(payload: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(CHUNK_RECORD_HEADER_SIZE + payload.len());
+    out.push(CHUNK_RECORD_TAG_DATA);
+    out.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    out.extend_from_slice(payload);
+    out
+}
+
+pub fn decode_chunk_record(record: &[u8]) -> Result<&[u8], CoreError> {
+    if record.len() < CHUNK_RECORD_HEADER_SIZE {
+        return Err(CoreError::TruncatedRecord);
+    }
+    if record[0] != CHUNK_RECORD_TAG_DATA {
+        return Err(CoreError::InvalidChunkTag);
+    }
+    let len = u32::from_le_bytes(record[1..5].try_into().unwrap()) as usize;
+    record.get(CHUNK_RECORD_HEADER_SIZE..CHUNK_RECORD_HEADER_SIZE + len).ok_or(CoreError::TruncatedRecord)
+}
+
+// Zero-run codec used by `to_bin_chunked_compressed`/`or_chunk_compressed`:
+// a zero run is tag 0x00 + varint word count, a literal run is tag 0x01 +
+// varint word count + that many raw little-endian u64s.
+pub const RUN_TAG_ZERO: u8 = 0x00;
+pub const RUN_TAG_LITERAL: u8 = 0x01;
+
+pub fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            break;
+        } else {
+            out.push(byte | 0x80);
+        }
+    }
+}
+
+// Returns `None` on a truncated or overlong (malformed) varint instead of
+// panicking, since the input may be a partial or corrupted transfer.
+pub fn read_varint(bin: &[u8], pos: &mut usize) -> Option<u64> {
+    let mut value: u64 = 0;
+    let mut shift = 0;
+    loop {
+        let byte = *bin.get(*pos)?;
+        *pos += 1;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            break;
+        }
+        shift += 7;
+        if shift >= 64 {
+            return None;
+        }
+    }
+    Some(value)
+}
+
+// Encodes a chunk's words as a zero-run/literal-run token stream instead of
+// raw bytes, so a sparsely-filled filter serializes to far fewer bytes.
+pub fn encode_chunk_compressed(words: &[u64]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < words.len() {
+        if words[i] == 0 {
+            let start = i;
+            while i < words.len() && words[i] == 0 {
+                i += 1;
+            }
+            out.push(RUN_TAG_ZERO);
+            write_varint(&mut out, (i - start) as u64);
+        } else {
+            let start = i;
+            while i < words.len() && words[i] != 0 {
+                i += 1;
+            }
+            out.push(RUN_TAG_LITERAL);
+            write_varint(&mut out, (i - start) as u64);
+            for word in &words[start..i] {
+                out.extend_from_slice(&word.to_le_bytes());
+            }
+        }
+    }
+    out
+}
+
+// Decodes a token stream produced by `encode_chunk_compressed` and ORs it
+// into `data` starting at `word_offset`: zero runs advance the word offset
+// with no writes, literal runs OR each decoded word in turn. The input may
+// be a truncated or corrupted transfer, so every read and every write index
+// is bounds-checked and rejected with the same error convention
+// `parse_header_bytes`/`merge_bytes_into` use, rather than panicking.
+pub fn decode_chunk_compressed(data: &[AtomicU64], dirty: &[AtomicU64], bin: &[u8], word_offset: usize) -> Result<usize, CoreError> {
+    let mut pos = 0usize;
+    let mut word_index = word_offset;
+
+    while pos < bin.len() {
+        let tag = bin[pos];
+        pos += 1;
+        let count = read_varint(bin, &mut pos).ok_or(CoreError::TruncatedChunk)? as usize;
+
+        match tag {
+            RUN_TAG_ZERO => {
+                word_index = word_index
+                    .checked_add(count)
+                    .filter(|&i| i <= data.len())
+                    .ok_or(CoreError::ChunkOverrunsArray)?;
+            }
+            RUN_TAG_LITERAL => {
+                for _ in 0..count {
+                    if word_index >= data.len() {
+                        return Err(CoreError::ChunkOverrunsArray);
+                    }
+                    let word_bytes = bin.get(pos..pos + 8).ok_or(CoreError::TruncatedChunk)?;
+                    let word = u64::from_le_bytes(word_bytes.try_into().unwrap());
+                    pos += 8;
+                    data[word_index].fetch_or(word, Ordering::Relaxed);
+                    mark_word_dirty(dirty, word_index);
+                    word_index += 1;
+                }
+            }
+            _ => return Err(CoreError::InvalidRunTag),
+        }
+    }
+
+    Ok(word_index)
+}
+
+// FNV-1a 64-bit, folded over a chunk's words. Fast and good enough to tell
+// two chunks apart for delta-sync purposes; not cryptographically strong.
+pub const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+pub const FNV_PRIME: u64 = 0x100000001b3;
+
+pub fn fnv1a_chunk(words: &[u64]) -> u64 {
+    let mut hash = FNV_OFFSET_BASIS;
+    for word in words {
+        for byte in word.to_le_bytes() {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(FNV_PRIME);
+        }
+    }
+    hash
+}
+
+// Shared by `put_many`/`add_member`/`check_member`: a caller-supplied bit
+// position whose word is out of range for `data` would otherwise index
+// straight past the end of the slice and panic the NIF, so every entry
+// point taking positions from the caller checks this first.
+pub fn check_indices_in_bounds(data_len: usize, indices: &[usize]) -> Result<(), CoreError> {
+    if indices.iter().any(|&index| index / 64 >= data_len) {
+        return Err(CoreError::IndexOutOfBounds);
+    }
+    Ok(())
+}
+
+pub fn put_many_impl(data: &[AtomicU64], dirty: &[AtomicU64], indices: &[usize]) -> Result<(), CoreError> {
+    use rayon::prelude::*;
+
+    check_indices_in_bounds(data.len(), indices)?;
+
+    indices.par_iter().for_each(|&index| {
+        let mask = 1u64 << (index % 64);
+        data[index / 64].fetch_or(mask, Ordering::Relaxed);
+        mark_word_dirty(dirty, index / 64);
+    });
+
+    Ok(())
+}
+
+pub fn add_member_impl(data: &[AtomicU64], dirty: &[AtomicU64], hash_positions: &[usize]) -> Result<(), CoreError> {
+    check_indices_in_bounds(data.len(), hash_positions)?;
+
+    for &index in hash_positions {
+        let mask = 1u64 << (index % 64);
+        data[index / 64].fetch_or(mask, Ordering::Relaxed);
+        mark_word_dirty(dirty, index / 64);
+    }
+
+    Ok(())
+}
+
+pub fn check_member_impl(data: &[AtomicU64], hash_positions: &[usize]) -> Result<bool, CoreError> {
+    check_indices_in_bounds(data.len(), hash_positions)?;
+
+    Ok(hash_positions.iter().all(|&index| {
+        let mask = 1u64 << (index % 64);
+        data[index / 64].load(Ordering::Relaxed) & mask != 0
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn varint_round_trips() {
+        for value in [0u64, 1, 63, 64, 127, 128, 300, 16384, u64::MAX] {
+            let mut out = Vec::new();
+            write_varint(&mut out, value);
+            let mut pos = 0;
+            assert_eq!(read_varint(&out, &mut pos), Some(value));
+            assert_eq!(pos, out.len());
+        }
+    }
+
+    #[test]
+    fn read_varint_rejects_truncated_input() {
+        // High bit set on the last byte with nothing after it.
+        let mut pos = 0;
+        assert_eq!(read_varint(&[0x80], &mut pos), None);
+    }
+
+    #[test]
+    fn compressed_chunk_round_trips() {
+        let words = vec![0u64, 0, 0xdead_beef, 0, 1, 1, 0];
+        let encoded = encode_chunk_compressed(&words);
+
+        let data: Vec<AtomicU64> = (0..words.len()).map(|_| AtomicU64::new(0)).collect();
+        let dirty: Vec<AtomicU64> = vec![AtomicU64::new(0)];
+        let next = decode_chunk_compressed(&data, &dirty, &encoded, 0).unwrap();
+
+        assert_eq!(next, words.len());
+        for (word, atomic) in words.iter().zip(data.iter()) {
+            assert_eq!(*word, atomic.load(Ordering::Relaxed));
+        }
+    }
+
+    #[test]
+    fn compressed_chunk_rejects_overrun() {
+        let words = vec![1u64; 4];
+        let encoded = encode_chunk_compressed(&words);
+
+        let data: Vec<AtomicU64> = (0..2).map(|_| AtomicU64::new(0)).collect();
+        let dirty: Vec<AtomicU64> = vec![AtomicU64::new(0)];
+        let err = decode_chunk_compressed(&data, &dirty, &encoded, 0).unwrap_err();
+
+        assert_eq!(err, CoreError::ChunkOverrunsArray);
+    }
+
+    #[test]
+    fn compressed_chunk_rejects_truncated_literal() {
+        let mut encoded = Vec::new();
+        encoded.push(RUN_TAG_LITERAL);
+        write_varint(&mut encoded, 1);
+        encoded.extend_from_slice(&[0u8; 3]); // only 3 of the 8 needed bytes
+
+        let data: Vec<AtomicU64> = vec![AtomicU64::new(0)];
+        let dirty: Vec<AtomicU64> = vec![AtomicU64::new(0)];
+        let err = decode_chunk_compressed(&data, &dirty, &encoded, 0).unwrap_err();
+
+        assert_eq!(err, CoreError::TruncatedChunk);
+    }
+
+    #[test]
+    fn header_round_trips() {
+        let bytes = build_header_bytes(256);
+        let header = parse_header_bytes(&bytes).unwrap();
+
+        assert_eq!(header.version, HEADER_VERSION);
+        assert_eq!(header.bit_length, 256);
+    }
+
+    #[test]
+    fn header_validation_rejects_bit_length_mismatch() {
+        let bytes = build_header_bytes(256);
+        let err = validate_header_bytes(&bytes, 512).unwrap_err();
+
+        assert_eq!(err, CoreError::BitLengthMismatch);
+    }
+
+    #[test]
+    fn header_validation_rejects_bad_magic() {
+        let mut bytes = build_header_bytes(256);
+        bytes[0] = b'X';
+
+        let err = validate_header_bytes(&bytes, 256).unwrap_err();
+
+        assert_eq!(err, CoreError::InvalidMagic);
+    }
+
+    #[test]
+    fn chunk_record_round_trips() {
+        let payload = vec![1u8, 2, 3, 4, 5];
+        let record = encode_chunk_record(&payload);
+
+        assert_eq!(decode_chunk_record(&record).unwrap(), &payload[..]);
+    }
+
+    #[test]
+    fn chunk_record_rejects_truncated_payload() {
+        let mut record = encode_chunk_record(&[1, 2, 3, 4]);
+        record.truncate(record.len() - 1);
+
+        let err = decode_chunk_record(&record).unwrap_err();
+
+        assert_eq!(err, CoreError::TruncatedRecord);
+    }
+
+    #[test]
+    fn merge_bytes_into_rejects_overrun() {
+        let data: Vec<AtomicU64> = vec![AtomicU64::new(0)];
+        let dirty: Vec<AtomicU64> = vec![AtomicU64::new(0)];
+
+        let err = merge_bytes_into(&data, &dirty, &[1, 2, 3, 4, 5, 6, 7, 8, 9], 0).unwrap_err();
+
+        assert_eq!(err, CoreError::ChunkOverrunsArray);
+    }
+
+    #[test]
+    fn put_many_sets_every_index_and_marks_chunks_dirty() {
+        let data: Vec<AtomicU64> = (0..4).map(|_| AtomicU64::new(0)).collect();
+        let dirty: Vec<AtomicU64> = vec![AtomicU64::new(0)];
+
+        put_many_impl(&data, &dirty, &[0, 63, 64, 200]).unwrap();
+
+        assert!(check_member_impl(&data, &[0, 63, 64, 200]).unwrap());
+        assert!(!check_member_impl(&data, &[1]).unwrap());
+        assert_eq!(dirty[0].load(Ordering::Relaxed), 0b1);
+    }
+
+    #[test]
+    fn put_many_rejects_out_of_bounds_index() {
+        let data: Vec<AtomicU64> = vec![AtomicU64::new(0)];
+        let dirty: Vec<AtomicU64> = vec![AtomicU64::new(0)];
+
+        let err = put_many_impl(&data, &dirty, &[0, 64]).unwrap_err();
+
+        assert_eq!(err, CoreError::IndexOutOfBounds);
+    }
+
+    #[test]
+    fn add_member_then_check_member_round_trips() {
+        let data: Vec<AtomicU64> = (0..2).map(|_| AtomicU64::new(0)).collect();
+        let dirty: Vec<AtomicU64> = vec![AtomicU64::new(0)];
+
+        add_member_impl(&data, &dirty, &[3, 70]).unwrap();
+
+        assert!(check_member_impl(&data, &[3, 70]).unwrap());
+        assert!(!check_member_impl(&data, &[3, 70, 4]).unwrap());
+    }
+
+    #[test]
+    fn add_member_rejects_out_of_bounds_position() {
+        let data: Vec<AtomicU64> = vec![AtomicU64::new(0)];
+        let dirty: Vec<AtomicU64> = vec![AtomicU64::new(0)];
+
+        let err = add_member_impl(&data, &dirty, &[64]).unwrap_err();
+
+        assert_eq!(err, CoreError::IndexOutOfBounds);
+    }
+
+    #[test]
+    fn check_member_rejects_out_of_bounds_position() {
+        let data: Vec<AtomicU64> = vec![AtomicU64::new(0)];
+
+        let err = check_member_impl(&data, &[64]).unwrap_err();
+
+        assert_eq!(err, CoreError::IndexOutOfBounds);
+    }
+
+    #[test]
+    fn check_members_reports_one_bool_per_element() {
+        let data: Vec<AtomicU64> = (0..2).map(|_| AtomicU64::new(0)).collect();
+        let dirty: Vec<AtomicU64> = vec![AtomicU64::new(0)];
+
+        add_member_impl(&data, &dirty, &[3, 70]).unwrap();
+
+        let results: Result<Vec<bool>, CoreError> = [vec![3, 70], vec![3, 4]]
+            .iter()
+            .map(|positions| check_member_impl(&data, positions))
+            .collect();
+
+        assert_eq!(results.unwrap(), vec![true, false]);
+    }
+
+    #[test]
+    fn num_chunks_rounds_up_to_a_partial_chunk() {
+        assert_eq!(num_chunks(0), 0);
+        assert_eq!(num_chunks(1), 1);
+        assert_eq!(num_chunks(CHUNK_SIZE_U64), 1);
+        assert_eq!(num_chunks(CHUNK_SIZE_U64 + 1), 2);
+    }
+
+    #[test]
+    fn fnv1a_chunk_is_deterministic() {
+        let words = vec![1u64, 2, 3];
+        assert_eq!(fnv1a_chunk(&words), fnv1a_chunk(&words));
+    }
+
+    #[test]
+    fn fnv1a_chunk_differs_for_different_words() {
+        assert_ne!(fnv1a_chunk(&[1u64, 2, 3]), fnv1a_chunk(&[1u64, 2, 4]));
+    }
+
+    #[test]
+    fn fnv1a_chunk_empty_is_the_offset_basis() {
+        assert_eq!(fnv1a_chunk(&[]), FNV_OFFSET_BASIS);
+    }
+}
@@ -1,17 +1,53 @@
 
 use rustler::{Atom, Env, NifResult, ResourceArc,Binary,OwnedBinary,Encoder, Term};
-use std::sync::Mutex;
 use std::cmp;
+use std::sync::atomic::{AtomicU64, Ordering};
+use bitarray_core::{CoreError, CHUNK_SIZE_U64, HEADER_SIZE};
+
 mod atoms {
     rustler::atoms! {
         ok,
-        eof
+        eof,
+        error,
+        version,
+        bit_length,
+        flags,
+        invalid_magic,
+        unsupported_version,
+        truncated_header,
+        bit_length_mismatch,
+        truncated_record,
+        invalid_chunk_tag,
+        chunk_overruns_array,
+        truncated_chunk,
+        invalid_run_tag,
+        index_out_of_bounds
     }
 }
+
+// Maps a bitarray-core error to this crate's NIF error atom.
+fn atom_for(err: CoreError) -> Atom {
+    match err {
+        CoreError::InvalidMagic => atoms::invalid_magic(),
+        CoreError::UnsupportedVersion => atoms::unsupported_version(),
+        CoreError::TruncatedHeader => atoms::truncated_header(),
+        CoreError::BitLengthMismatch => atoms::bit_length_mismatch(),
+        CoreError::TruncatedRecord => atoms::truncated_record(),
+        CoreError::InvalidChunkTag => atoms::invalid_chunk_tag(),
+        CoreError::ChunkOverrunsArray => atoms::chunk_overruns_array(),
+        CoreError::TruncatedChunk => atoms::truncated_chunk(),
+        CoreError::InvalidRunTag => atoms::invalid_run_tag(),
+        CoreError::IndexOutOfBounds => atoms::index_out_of_bounds(),
+    }
+}
+
 struct BitArray {
-    pub data: Mutex<Box<[u64]>>,
+    pub data: Box<[AtomicU64]>,
+    // One bit per CHUNK_SIZE_U64-word chunk, set whenever `put`/`put_many`/
+    // `add_member`/`or_chunk` touch a word inside that chunk. Lets callers
+    // snapshot or replicate only the chunks that changed since the last flush.
+    pub dirty: Box<[AtomicU64]>,
 }
-const CHUNK_SIZE_U64: usize = 1024;
 
 #[rustler::nif]
 fn add(a: i64, b: i64) -> i64 {
@@ -28,33 +64,93 @@ fn sub(a: i64, b: i64) -> i64 {
 
 #[rustler::nif]
 fn new(length: usize) -> NifResult<ResourceArc<BitArray>> {
-    let data: Box<[u64]> = vec![0; (length + 63) / 64].into_boxed_slice();
+    let num_words = length.div_ceil(64);
+    let data: Box<[AtomicU64]> = (0..num_words).map(|_| AtomicU64::new(0)).collect();
+    let dirty: Box<[AtomicU64]> = (0..bitarray_core::num_chunks(num_words).div_ceil(64)).map(|_| AtomicU64::new(0)).collect();
     // println!("{:?}", data);
-    let resource: ResourceArc<BitArray> = ResourceArc::new(BitArray {
-        data: Mutex::new(data),
-    });
+    let resource: ResourceArc<BitArray> = ResourceArc::new(BitArray { data, dirty });
     Ok(resource)
 }
 
 #[rustler::nif]
-fn put(resource: ResourceArc<BitArray>, index: usize, value: bool) -> Atom {
-    let mut vec = resource.data.lock().unwrap();
-    let mut word = vec[index / 64];
+fn put<'a>(env: Env<'a>, resource: ResourceArc<BitArray>, index: usize, value: bool) -> Term<'a> {
+    if let Err(reason) = bitarray_core::check_indices_in_bounds(resource.data.len(), &[index]) {
+        return (atoms::error(), atom_for(reason)).encode(env);
+    }
+
+    let mask = 1u64 << (index % 64);
 
     if value {
-        word |= 1 << (index % 64);
+        resource.data[index / 64].fetch_or(mask, Ordering::Relaxed);
     } else {
-        word &= !(1 << (index % 64));
+        resource.data[index / 64].fetch_and(!mask, Ordering::Relaxed);
     }
+    bitarray_core::mark_word_dirty(&resource.dirty, index / 64);
 
-    vec[index / 64] = word;
+    atoms::ok().encode(env)
+}
 
-    atoms::ok()
+// Sets every bit position touched by `indices` in parallel across a rayon
+// thread pool. Each word is only ever combined with `fetch_or`, so threads
+// landing on the same word race safely without any coordination.
+#[rustler::nif]
+fn put_many<'a>(env: Env<'a>, resource: ResourceArc<BitArray>, indices: Vec<usize>) -> Term<'a> {
+    match bitarray_core::put_many_impl(&resource.data, &resource.dirty, &indices) {
+        Ok(()) => atoms::ok().encode(env),
+        Err(reason) => (atoms::error(), atom_for(reason)).encode(env),
+    }
+}
+
+// Sets all k bit positions for a single Bloom element under one NIF call,
+// instead of one `put` per hash position.
+#[rustler::nif]
+fn add_member<'a>(env: Env<'a>, resource: ResourceArc<BitArray>, hash_positions: Vec<usize>) -> Term<'a> {
+    match bitarray_core::add_member_impl(&resource.data, &resource.dirty, &hash_positions) {
+        Ok(()) => atoms::ok().encode(env),
+        Err(reason) => (atoms::error(), atom_for(reason)).encode(env),
+    }
+}
+
+// Checks all k bit positions for a single Bloom element under one NIF call,
+// short-circuiting to `false` on the first clear bit.
+#[rustler::nif]
+fn check_member<'a>(env: Env<'a>, resource: ResourceArc<BitArray>, hash_positions: Vec<usize>) -> Term<'a> {
+    match bitarray_core::check_member_impl(&resource.data, &hash_positions) {
+        Ok(is_member) => (atoms::ok(), is_member).encode(env),
+        Err(reason) => (atoms::error(), atom_for(reason)).encode(env),
+    }
+}
+
+// Checks membership for many elements at once, each given as its own list of
+// hash positions, returning one bool per element in the same order. Rejects
+// the whole batch if any element's positions are out of bounds.
+#[rustler::nif]
+fn check_members<'a>(env: Env<'a>, resource: ResourceArc<BitArray>, lists_of_positions: Vec<Vec<usize>>) -> Term<'a> {
+    let results: Result<Vec<bool>, CoreError> = lists_of_positions
+        .iter()
+        .map(|hash_positions| bitarray_core::check_member_impl(&resource.data, hash_positions))
+        .collect();
+
+    match results {
+        Ok(values) => (atoms::ok(), values).encode(env),
+        Err(reason) => (atoms::error(), atom_for(reason)).encode(env),
+    }
 }
 
 #[rustler::nif]
 fn to_bin_chunked(env: Env, resource: ResourceArc<BitArray>, chunk_num: usize) -> NifResult<(Term, Binary)> {
-    let data = resource.data.lock().unwrap();
+    let data = &resource.data;
+
+    // Same out-of-range guard as `to_bin_chunked_clear_dirty`/
+    // `to_bin_chunked_compressed`/`to_bin_chunked_record`: `chunk_num` is
+    // caller-supplied rather than derived from a bounds-checked byte range,
+    // so without this check a `chunk_num` past the end of the array wraps
+    // `reminding`/`size` and panics on the allocation below instead of
+    // handing back an error atom.
+    if chunk_num >= bitarray_core::num_chunks(data.len()) {
+        return Err(rustler::Error::Term(Box::new(atoms::chunk_overruns_array())));
+    }
+
     let offset = chunk_num * CHUNK_SIZE_U64;
     let reminding = (data.len() as isize) - (offset as isize);
     let size = std::cmp::min(CHUNK_SIZE_U64 as isize, reminding) as usize;
@@ -65,9 +161,10 @@ fn to_bin_chunked(env: Env, resource: ResourceArc<BitArray>, chunk_num: usize) -
     let bin = erl_bin.as_mut_slice();
 
     for x in 0..size {
+        let word = data[x + offset].load(Ordering::Relaxed);
         for y in 0..8 {
             let i = x * 8 + y;
-            bin[i] = (data[x + offset] >> (y * 8)) as u8;
+            bin[i] = (word >> (y * 8)) as u8;
         }
     }
     if is_eof {
@@ -75,45 +172,294 @@ fn to_bin_chunked(env: Env, resource: ResourceArc<BitArray>, chunk_num: usize) -
     } else {
         Ok(((chunk_num + 1).encode(env), erl_bin.release(env)))
     }
-  
+
 }
 
+// Same as `to_bin_chunked`, but clears the dirty bit for the chunk it emits,
+// so a caller streaming chunks out for a checkpoint can mark them flushed as
+// it goes instead of calling `clear_dirty` separately afterwards.
+#[rustler::nif]
+fn to_bin_chunked_clear_dirty(env: Env, resource: ResourceArc<BitArray>, chunk_num: usize) -> NifResult<(Term, Binary)> {
+    let data = &resource.data;
+
+    // Unlike `or_chunk`/`or_chunk_compressed`/`or_chunk_record`, this takes
+    // a caller-supplied `chunk_num` directly rather than deriving an index
+    // from a bounds-checked byte range, so it needs its own explicit check:
+    // a `chunk_num` past the end of the array would otherwise index
+    // `resource.dirty` out of bounds and panic the NIF instead of handing
+    // back an error atom.
+    if chunk_num >= bitarray_core::num_chunks(data.len()) {
+        return Err(rustler::Error::Term(Box::new(atoms::chunk_overruns_array())));
+    }
+
+    let offset = chunk_num * CHUNK_SIZE_U64;
+    let reminding = (data.len() as isize) - (offset as isize);
+    let size = std::cmp::min(CHUNK_SIZE_U64 as isize, reminding) as usize;
+    let is_eof = reminding <= (CHUNK_SIZE_U64 as isize);
+
+    // Clear the dirty bit before reading, not after, so a concurrent write to
+    // this chunk is never lost to either ordering. AcqRel pairs with the
+    // Release in `mark_chunk_dirty` so an observed dirty bit also guarantees
+    // its data write is visible to the `load` below.
+    let mask = !(1u64 << (chunk_num % 64));
+    resource.dirty[chunk_num / 64].fetch_and(mask, Ordering::AcqRel);
+
+    let erl_bin_size = size * 8;
+    let mut erl_bin = OwnedBinary::new(erl_bin_size).ok_or_else(|| rustler::Error::Term(Box::new("Binary alloc failed")))?;
+    let bin = erl_bin.as_mut_slice();
+
+    for x in 0..size {
+        let word = data[x + offset].load(Ordering::Relaxed);
+        for y in 0..8 {
+            let i = x * 8 + y;
+            bin[i] = (word >> (y * 8)) as u8;
+        }
+    }
+
+    if is_eof {
+        Ok((atoms::eof().encode(env), erl_bin.release(env)))
+    } else {
+        Ok(((chunk_num + 1).encode(env), erl_bin.release(env)))
+    }
+}
 
+// Returns an 8-byte FNV-1a digest per chunk, packed little-endian in chunk
+// order, so a peer can diff digest lists and request only the chunks whose
+// contents actually differ instead of transferring the whole array.
 #[rustler::nif]
-fn or_chunk(resource: ResourceArc<BitArray>, bin: Binary, byte_offset: usize) -> NifResult<usize> {
-    let mut data = resource.data.lock().unwrap();
+fn chunk_digests(env: Env, resource: ResourceArc<BitArray>) -> NifResult<Binary> {
+    let data = &resource.data;
+    let total_chunks = bitarray_core::num_chunks(data.len());
 
-    for x in 0..bin.len() {
-        let data_index = (x + byte_offset) / 8;
-        let bin_offset = (x + byte_offset) % 8;
+    let mut erl_bin = OwnedBinary::new(total_chunks * 8)
+        .ok_or_else(|| rustler::Error::Term(Box::new("Binary alloc failed")))?;
+    let bin = erl_bin.as_mut_slice();
 
-        data[data_index] |= (bin[x] as u64) << (bin_offset * 8);
+    for chunk_num in 0..total_chunks {
+        let offset = chunk_num * CHUNK_SIZE_U64;
+        let size = cmp::min(CHUNK_SIZE_U64, data.len() - offset);
+        let words: Vec<u64> = (0..size).map(|x| data[x + offset].load(Ordering::Relaxed)).collect();
+        let digest = bitarray_core::fnv1a_chunk(&words);
+        bin[chunk_num * 8..chunk_num * 8 + 8].copy_from_slice(&digest.to_le_bytes());
     }
 
-    Ok(byte_offset + bin.len())
+    Ok(erl_bin.release(env))
+}
+
+// Compressed counterpart of `to_bin_chunked`.
+#[rustler::nif]
+fn to_bin_chunked_compressed(env: Env, resource: ResourceArc<BitArray>, chunk_num: usize) -> NifResult<(Term, Binary)> {
+    let data = &resource.data;
+
+    // Same out-of-range guard as `to_bin_chunked_clear_dirty`: `chunk_num` is
+    // caller-supplied rather than derived from a bounds-checked byte range,
+    // so without this check a `chunk_num` past the end of the array wraps
+    // `reminding`/`size` and panics on the allocation below instead of
+    // handing back an error atom.
+    if chunk_num >= bitarray_core::num_chunks(data.len()) {
+        return Err(rustler::Error::Term(Box::new(atoms::chunk_overruns_array())));
+    }
+
+    let offset = chunk_num * CHUNK_SIZE_U64;
+    let reminding = (data.len() as isize) - (offset as isize);
+    let size = std::cmp::min(CHUNK_SIZE_U64 as isize, reminding) as usize;
+    let is_eof = reminding <= (CHUNK_SIZE_U64 as isize);
+
+    let words: Vec<u64> = (0..size).map(|x| data[x + offset].load(Ordering::Relaxed)).collect();
+    let out = bitarray_core::encode_chunk_compressed(&words);
+
+    let mut erl_bin = OwnedBinary::new(out.len()).ok_or_else(|| rustler::Error::Term(Box::new("Binary alloc failed")))?;
+    erl_bin.as_mut_slice().copy_from_slice(&out);
+
+    if is_eof {
+        Ok((atoms::eof().encode(env), erl_bin.release(env)))
+    } else {
+        Ok(((chunk_num + 1).encode(env), erl_bin.release(env)))
+    }
+}
+
+// Merges a token stream produced by `to_bin_chunked_compressed`, validating
+// `header_bin` against this resource the same way `or_chunk` does before
+// decoding. Returns `{:ok, next_word_offset} | {:error, reason}` — the same
+// atom-based error convention `parse_header` uses, rather than
+// raising an exception, since malformed input here is an expected case, not
+// a programming error.
+#[rustler::nif]
+fn or_chunk_compressed<'a>(env: Env<'a>, resource: ResourceArc<BitArray>, header_bin: Binary<'a>, bin: Binary<'a>, word_offset: usize) -> Term<'a> {
+    let target_bit_length = (resource.data.len() * 64) as u64;
+
+    if let Err(reason) = bitarray_core::validate_header_bytes(&header_bin, target_bit_length) {
+        return (atoms::error(), atom_for(reason)).encode(env);
+    }
+
+    match bitarray_core::decode_chunk_compressed(&resource.data, &resource.dirty, &bin, word_offset) {
+        Ok(next_word_offset) => (atoms::ok(), next_word_offset).encode(env),
+        Err(reason) => (atoms::error(), atom_for(reason)).encode(env),
+    }
+}
+
+// Returns the chunk numbers that have changed since the last flush (the last
+// `clear_dirty` or `to_bin_chunked_clear_dirty` call that touched them), so
+// callers can persist or replicate only the chunks that actually changed.
+#[rustler::nif]
+fn dirty_chunks(resource: ResourceArc<BitArray>) -> Vec<usize> {
+    let total_chunks = bitarray_core::num_chunks(resource.data.len());
+    let mut chunks = Vec::new();
+
+    for chunk_num in 0..total_chunks {
+        let word = resource.dirty[chunk_num / 64].load(Ordering::Relaxed);
+        if word & (1u64 << (chunk_num % 64)) != 0 {
+            chunks.push(chunk_num);
+        }
+    }
+
+    chunks
+}
+
+// Clears every dirty bit, marking the whole filter as flushed.
+#[rustler::nif]
+fn clear_dirty(resource: ResourceArc<BitArray>) -> Atom {
+    for word in resource.dirty.iter() {
+        word.store(0, Ordering::Relaxed);
+    }
+
+    atoms::ok()
+}
+
+// Builds the container header for this resource: magic, format version,
+// flags, and the total bit length, so a serialized filter is self-describing.
+#[rustler::nif]
+fn header(env: Env, resource: ResourceArc<BitArray>) -> NifResult<Binary> {
+    let bit_length = (resource.data.len() * 64) as u64;
+
+    let mut erl_bin = OwnedBinary::new(HEADER_SIZE)
+        .ok_or_else(|| rustler::Error::Term(Box::new("Binary alloc failed")))?;
+    erl_bin.as_mut_slice().copy_from_slice(&bitarray_core::build_header_bytes(bit_length));
+
+    Ok(erl_bin.release(env))
+}
+
+// Parses a container header, returning `{:ok, %{version:, bit_length:, flags:}}`
+// or `{:error, reason}` for a bad magic, unsupported version, or truncated binary.
+#[rustler::nif]
+fn parse_header<'a>(env: Env<'a>, bin: Binary<'a>) -> Term<'a> {
+    match bitarray_core::parse_header_bytes(&bin) {
+        Ok(h) => {
+            let map = Term::map_new(env)
+                .map_put(atoms::version(), h.version).unwrap()
+                .map_put(atoms::flags(), h.flags).unwrap()
+                .map_put(atoms::bit_length(), h.bit_length).unwrap();
+            (atoms::ok(), map).encode(env)
+        }
+        Err(reason) => (atoms::error(), atom_for(reason)).encode(env),
+    }
+}
+
+// `header_bin` (as produced by `header/1` on the sender) is checked against
+// this resource's magic/version/bit_length on every call. `or_chunk_record`
+// and `or_chunk_compressed` take the same `header_bin` argument and run the
+// same check before merging their payload, so every merge entry point
+// validates, not just this one.
+#[rustler::nif]
+fn or_chunk<'a>(env: Env<'a>, resource: ResourceArc<BitArray>, header_bin: Binary<'a>, bin: Binary<'a>, byte_offset: usize) -> Term<'a> {
+    let target_bit_length = (resource.data.len() * 64) as u64;
+
+    if let Err(reason) = bitarray_core::validate_header_bytes(&header_bin, target_bit_length) {
+        return (atoms::error(), atom_for(reason)).encode(env);
+    }
+
+    match bitarray_core::merge_bytes_into(&resource.data, &resource.dirty, &bin, byte_offset) {
+        Ok(next_byte_offset) => (atoms::ok(), next_byte_offset).encode(env),
+        Err(reason) => (atoms::error(), atom_for(reason)).encode(env),
+    }
+}
+
+// Raw (uncompressed) RIFF-style chunk record for `to_bin_chunked_record`'s
+// companion `or_chunk_record`: a tag + u32 length prefix around the raw
+// chunk bytes, so each chunk is self-delimiting on the wire.
+#[rustler::nif]
+fn to_bin_chunked_record(env: Env, resource: ResourceArc<BitArray>, chunk_num: usize) -> NifResult<(Term, Binary)> {
+    let data = &resource.data;
+
+    // Same out-of-range guard as `to_bin_chunked_clear_dirty`: `chunk_num` is
+    // caller-supplied rather than derived from a bounds-checked byte range,
+    // so without this check a `chunk_num` past the end of the array wraps
+    // `reminding`/`size` and panics on the `vec![0u8; size * 8]` allocation
+    // below instead of handing back an error atom.
+    if chunk_num >= bitarray_core::num_chunks(data.len()) {
+        return Err(rustler::Error::Term(Box::new(atoms::chunk_overruns_array())));
+    }
+
+    let offset = chunk_num * CHUNK_SIZE_U64;
+    let reminding = (data.len() as isize) - (offset as isize);
+    let size = std::cmp::min(CHUNK_SIZE_U64 as isize, reminding) as usize;
+    let is_eof = reminding <= (CHUNK_SIZE_U64 as isize);
+
+    let mut payload = vec![0u8; size * 8];
+    for x in 0..size {
+        let word = data[x + offset].load(Ordering::Relaxed);
+        for y in 0..8 {
+            payload[x * 8 + y] = (word >> (y * 8)) as u8;
+        }
+    }
+    let record = bitarray_core::encode_chunk_record(&payload);
+
+    let mut erl_bin = OwnedBinary::new(record.len()).ok_or_else(|| rustler::Error::Term(Box::new("Binary alloc failed")))?;
+    erl_bin.as_mut_slice().copy_from_slice(&record);
+
+    if is_eof {
+        Ok((atoms::eof().encode(env), erl_bin.release(env)))
+    } else {
+        Ok(((chunk_num + 1).encode(env), erl_bin.release(env)))
+    }
+}
+
+// Merges a record produced by `to_bin_chunked_record`, validating `header_bin`
+// against this resource the same way `or_chunk` does, then the record's own
+// tag and length framing, then bounds-checking the write range against this
+// resource's array, returning `{:ok, next_byte_offset} | {:error, reason}`.
+#[rustler::nif]
+fn or_chunk_record<'a>(env: Env<'a>, resource: ResourceArc<BitArray>, header_bin: Binary<'a>, record_bin: Binary<'a>, byte_offset: usize) -> Term<'a> {
+    let target_bit_length = (resource.data.len() * 64) as u64;
+
+    if let Err(reason) = bitarray_core::validate_header_bytes(&header_bin, target_bit_length) {
+        return (atoms::error(), atom_for(reason)).encode(env);
+    }
+
+    let payload = match bitarray_core::decode_chunk_record(&record_bin) {
+        Ok(payload) => payload,
+        Err(reason) => return (atoms::error(), atom_for(reason)).encode(env),
+    };
+
+    match bitarray_core::merge_bytes_into(&resource.data, &resource.dirty, payload, byte_offset) {
+        Ok(next_byte_offset) => (atoms::ok(), next_byte_offset).encode(env),
+        Err(reason) => (atoms::error(), atom_for(reason)).encode(env),
+    }
 }
 
 #[rustler::nif]
 fn count_ones(resource: ResourceArc<BitArray>) -> usize {
-    let data = resource.data.lock().unwrap();
-    data.iter().map(|x| x.count_ones() as usize).sum()
+    resource.data.iter().map(|x| x.load(Ordering::Relaxed).count_ones() as usize).sum()
 }
 
 #[rustler::nif]
-fn get(resource: ResourceArc<BitArray>, index: usize) -> bool {
-    let data = resource.data.lock().unwrap();
-    (data[index / 64] & (1 << (index % 64))) != 0
+fn get<'a>(env: Env<'a>, resource: ResourceArc<BitArray>, index: usize) -> Term<'a> {
+    if let Err(reason) = bitarray_core::check_indices_in_bounds(resource.data.len(), &[index]) {
+        return (atoms::error(), atom_for(reason)).encode(env);
+    }
+
+    let is_set = (resource.data[index / 64].load(Ordering::Relaxed) & (1 << (index % 64))) != 0;
+    (atoms::ok(), is_set).encode(env)
 }
 
 #[rustler::nif]
 fn bit_length(resource: ResourceArc<BitArray>) -> usize {
-    let data = resource.data.lock().unwrap();
-    data.len() * 64
+    resource.data.len() * 64
 }
 
 #[rustler::nif]
-fn count_ones_chunked(env: Env, resource: ResourceArc<BitArray>, chunk_num: usize) -> NifResult<(Term)> {
-    let data = resource.data.lock().unwrap();
+fn count_ones_chunked(env: Env, resource: ResourceArc<BitArray>, chunk_num: usize) -> NifResult<Term> {
+    let data = &resource.data;
 
     let offset = chunk_num * CHUNK_SIZE_U64;
     let remaining = data.len().saturating_sub(offset);
@@ -123,7 +469,7 @@ fn count_ones_chunked(env: Env, resource: ResourceArc<BitArray>, chunk_num: usiz
     let mut count = 0usize;
 
     for x in 0..size {
-        count += data[x + offset].count_ones() as usize;
+        count += data[x + offset].load(Ordering::Relaxed).count_ones() as usize;
     }
 
     // let env = unsafe { rustler::Env::new() };
@@ -133,6 +479,11 @@ fn count_ones_chunked(env: Env, resource: ResourceArc<BitArray>, chunk_num: usiz
         Ok((chunk_num + 1, count).encode(env))
     }
 }
+
+// rustler::resource! expands to an impl for a foreign type, which trips
+// rustc's non_local_definitions/unused_must_use lints on this rustler
+// version; the macro's expansion isn't ours to restructure.
+#[allow(unused_must_use, non_local_definitions)]
 fn on_load(env: Env, _term: Term) -> bool {
     rustler::resource!(BitArray, env);
     true